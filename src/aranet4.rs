@@ -1,9 +1,40 @@
 use crate::{Error, Result};
-use scroll::{ctx::TryFromCtx, Endian, Pread};
+use anyhow::anyhow;
+use bluez_async::{BluetoothSession, DeviceId};
+use chrono::{DateTime, Duration, Utc};
+use scroll::{
+    ctx::{TryFromCtx, TryIntoCtx},
+    Endian, Pread, Pwrite,
+};
 use serde::Serialize;
+use tracing::debug;
+use uuid::Uuid;
 
 pub const MANUFACTURER_ID: u16 = 0x0702;
 
+/// GATT service exposing the Aranet4's configuration and logged history.
+pub const SERVICE: Uuid = Uuid::from_u128(0xf0cd1400_95da_4f4b_9ac8_aa55d312af0c);
+/// Number of readings the device currently holds in its log (`u16`).
+const TOTAL_READINGS: Uuid = Uuid::from_u128(0xf0cd2001_95da_4f4b_9ac8_aa55d312af0c);
+/// Detailed current readings, including the measurement interval and the
+/// number of seconds since the last update, used to anchor history timestamps.
+const CURRENT_READINGS: Uuid = Uuid::from_u128(0xf0cd3001_95da_4f4b_9ac8_aa55d312af0c);
+/// Characteristic commands (history requests and configuration writes) are
+/// written to.
+const WRITE: Uuid = Uuid::from_u128(0xf0cd1402_95da_4f4b_9ac8_aa55d312af0c);
+/// Readable characteristic the device echoes command output to: fragmented
+/// history packets, and the acknowledgement of a configuration write.
+const READ: Uuid = Uuid::from_u128(0xf0cd2005_95da_4f4b_9ac8_aa55d312af0c);
+
+/// Opcode requesting a range of logged values for a single parameter.
+const CMD_READ_HISTORY: u8 = 0x61;
+/// Opcode setting the measurement interval (body: `u16` seconds).
+const CMD_SET_INTERVAL: u8 = 0x90;
+/// Opcode toggling the smart-home integration broadcast (body: `u8` 0/1).
+const CMD_SMART_HOME: u8 = 0x91;
+/// Opcode triggering a manual CO2 calibration (no body).
+const CMD_CALIBRATE: u8 = 0x92;
+
 #[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Announcement {
     pub co2: Option<u16>,
@@ -38,10 +69,273 @@ impl<'a> TryFromCtx<'a, ()> for Announcement {
     }
 }
 
+/// A logged parameter. The ids match the ones the device expects in the
+/// history command and echoes back in each packet header.
+#[derive(Debug, Copy, Clone)]
+enum Parameter {
+    Temperature = 1,
+    Humidity = 2,
+    Pressure = 3,
+    Co2 = 4,
+}
+
+impl Parameter {
+    /// Decode one logged value at `offset`, scaling it exactly like the
+    /// matching advertisement field, and store it in `sample`.
+    fn apply(self, sample: &mut HistorySample, from: &[u8], offset: &mut usize) -> Result<()> {
+        match self {
+            Parameter::Temperature => {
+                sample.temperature =
+                    Some(from.gread_with::<u16>(offset, Endian::Little)? as f64 * 0.05)
+            }
+            Parameter::Humidity => sample.humidity = Some(from.gread(offset)?),
+            Parameter::Pressure => {
+                sample.pressure =
+                    Some(from.gread_with::<u16>(offset, Endian::Little)? as f64 * 0.1)
+            }
+            Parameter::Co2 => sample.co2 = Some(from.gread_with::<u16>(offset, Endian::Little)?),
+        }
+        Ok(())
+    }
+}
+
+/// A single logged reading. Each field is optional because the device is
+/// polled for one parameter at a time and a log may predate some sensors.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct HistorySample {
+    pub co2: Option<u16>,
+    pub temperature: Option<f64>,
+    pub pressure: Option<f64>,
+    pub humidity: Option<u8>,
+}
+
+/// The backfilled time series pulled from a device's log over an active
+/// connection, newest sample last.
+#[derive(Debug, Clone, Serialize)]
+pub struct History {
+    pub samples: Vec<(DateTime<Utc>, HistorySample)>,
+}
+
+/// Connect to an Aranet4 and download its entire logged history.
+///
+/// This interrupts the device's own logging cadence for the duration of the
+/// connection, so it is only driven when the daemon is asked to.
+pub async fn fetch_history(session: &BluetoothSession, device: &DeviceId) -> Result<History> {
+    session.connect(device).await?;
+
+    let total = {
+        let c = session
+            .get_service_characteristic_by_uuid(device, SERVICE, TOTAL_READINGS)
+            .await?;
+        session
+            .read_characteristic_value(&c.id)
+            .await?
+            .pread_with::<u16>(0, Endian::Little)?
+    };
+
+    // The detailed current readings give us the logging interval and how long
+    // ago the newest sample was taken, which anchors every timestamp.
+    let (interval, ago) = {
+        let c = session
+            .get_service_characteristic_by_uuid(device, SERVICE, CURRENT_READINGS)
+            .await?;
+        let value = session.read_characteristic_value(&c.id).await?;
+        (
+            value.pread_with::<u16>(9, Endian::Little)?,
+            value.pread_with::<u16>(11, Endian::Little)?,
+        )
+    };
+    debug!(dev = %device, total, interval, ago, "⬇️ Downloading Aranet4 history");
+
+    let mut samples = vec![HistorySample::default(); total as usize];
+    for param in [
+        Parameter::Temperature,
+        Parameter::Humidity,
+        Parameter::Pressure,
+        Parameter::Co2,
+    ] {
+        read_parameter(session, device, param, total, &mut samples).await?;
+    }
+
+    // The newest sample is `ago` seconds old; earlier ones step back by the
+    // logging interval.
+    let last = Utc::now() - Duration::seconds(ago as i64);
+    let samples = samples
+        .into_iter()
+        .enumerate()
+        .map(|(i, sample)| {
+            let steps = total as i64 - 1 - i as i64;
+            (last - Duration::seconds(interval as i64 * steps), sample)
+        })
+        .collect();
+
+    session.disconnect(device).await?;
+    Ok(History { samples })
+}
+
+/// Ask for a single parameter's log and reassemble the fragmented packets
+/// into `samples`, which is indexed by the log position the device reports.
+async fn read_parameter(
+    session: &BluetoothSession,
+    device: &DeviceId,
+    param: Parameter,
+    total: u16,
+    samples: &mut [HistorySample],
+) -> Result<()> {
+    let write = session
+        .get_service_characteristic_by_uuid(device, SERVICE, WRITE)
+        .await?;
+    let read = session
+        .get_service_characteristic_by_uuid(device, SERVICE, READ)
+        .await?;
+
+    let mut command = Vec::with_capacity(6);
+    command.push(CMD_READ_HISTORY);
+    command.push(param as u8);
+    command.extend_from_slice(&0u16.to_le_bytes());
+    command.extend_from_slice(&total.to_le_bytes());
+    session.write_characteristic_value(&write.id, command).await?;
+
+    let mut seen = 0u16;
+    while seen < total {
+        let packet = session.read_characteristic_value(&read.id).await?;
+        let mut offset = 0;
+        let pid: u8 = packet.gread(&mut offset)?;
+        if pid != param as u8 {
+            return Err(anyhow!(
+                "history packet for parameter {pid}, expected {}",
+                param as u8
+            ));
+        }
+        let start: u16 = packet.gread_with(&mut offset, Endian::Little)?;
+        let count: u8 = packet.gread(&mut offset)?;
+        for i in 0..count {
+            let index = start as usize + i as usize;
+            if let Some(sample) = samples.get_mut(index) {
+                param.apply(sample, &packet, &mut offset)?;
+            }
+        }
+        // Guard against a zero-count or repeated packet that would otherwise
+        // spin this loop forever reading the same characteristic.
+        let next = start + count as u16;
+        if next <= seen {
+            return Err(anyhow!(
+                "history packet made no progress (start {start}, count {count})"
+            ));
+        }
+        seen = next;
+    }
+    Ok(())
+}
+
+/// A configuration command written to the device's write characteristic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Set the measurement interval, in seconds.
+    SetInterval(u16),
+    /// Enable or disable the smart-home integration broadcast, which controls
+    /// whether CO2/temperature/pressure appear in the advertisement.
+    SmartHome(bool),
+    /// Trigger a manual CO2 calibration.
+    Calibrate,
+}
+
+impl TryIntoCtx<()> for Command {
+    type Error = Error;
+    fn try_into_ctx(self, dst: &mut [u8], _: ()) -> Result<usize> {
+        let mut offset = 0;
+        match self {
+            Command::SetInterval(seconds) => {
+                dst.gwrite(CMD_SET_INTERVAL, &mut offset)?;
+                dst.gwrite_with(seconds, &mut offset, Endian::Little)?;
+            }
+            Command::SmartHome(enabled) => {
+                dst.gwrite(CMD_SMART_HOME, &mut offset)?;
+                dst.gwrite(enabled as u8, &mut offset)?;
+            }
+            Command::Calibrate => {
+                dst.gwrite(CMD_CALIBRATE, &mut offset)?;
+            }
+        }
+        Ok(offset)
+    }
+}
+
+/// The device's acknowledgement of a configuration command.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CommandResponse {
+    pub opcode: u8,
+    pub status: u8,
+}
+
+impl<'a> TryFromCtx<'a, ()> for CommandResponse {
+    type Error = Error;
+    fn try_from_ctx(from: &'a [u8], _: ()) -> Result<(Self, usize)> {
+        let mut offset = 0;
+        Ok((
+            Self {
+                opcode: from.gread(&mut offset)?,
+                status: from.gread(&mut offset)?,
+            },
+            offset,
+        ))
+    }
+}
+
+/// Connect to an Aranet4 and apply each configuration command in turn, reading
+/// back the device's acknowledgement for each.
+pub async fn configure(
+    session: &BluetoothSession,
+    device: &DeviceId,
+    commands: &[Command],
+) -> Result<Vec<CommandResponse>> {
+    session.connect(device).await?;
+    let write = session
+        .get_service_characteristic_by_uuid(device, SERVICE, WRITE)
+        .await?;
+    // The write characteristic is write-only; the device acknowledges on the
+    // readable response characteristic instead.
+    let read = session
+        .get_service_characteristic_by_uuid(device, SERVICE, READ)
+        .await?;
+
+    let mut responses = Vec::with_capacity(commands.len());
+    for command in commands {
+        let mut buffer = [0u8; 8];
+        let len = buffer.pwrite_with(*command, 0, ())?;
+        session
+            .write_characteristic_value(&write.id, buffer[..len].to_vec())
+            .await?;
+
+        let response = session
+            .read_characteristic_value(&read.id)
+            .await?
+            .pread::<CommandResponse>(0)?;
+        debug!(dev = %device, ?command, ?response, "⚙️ Aranet4 command");
+        responses.push(response);
+    }
+
+    session.disconnect(device).await?;
+    Ok(responses)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_command_serialization() {
+        let mut buffer = [0u8; 8];
+        let len = buffer.pwrite_with(Command::SetInterval(60), 0, ()).unwrap();
+        assert_eq!(&buffer[..len], &[0x90, 0x3c, 0x00]);
+
+        let len = buffer.pwrite_with(Command::SmartHome(true), 0, ()).unwrap();
+        assert_eq!(&buffer[..len], &[0x91, 0x01]);
+
+        let len = buffer.pwrite_with(Command::Calibrate, 0, ()).unwrap();
+        assert_eq!(&buffer[..len], &[0x92]);
+    }
+
     #[test]
     fn test_announcement() {
         assert_eq!(
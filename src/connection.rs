@@ -0,0 +1,149 @@
+use bluez_async::{BluetoothEvent, BluetoothSession, DeviceEvent, DeviceId};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::sleep;
+use tokio_stream::StreamExt;
+use tracing::{debug, warn};
+
+/// Base reconnect delay after a drop; jitter is added on top.
+const BACKOFF_START: Duration = Duration::from_secs(1);
+/// The base delay never grows past this; jitter can add up to as much again.
+const BACKOFF_CAP: Duration = Duration::from_secs(60);
+
+/// Where a tracked device is in its connection lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Discovered,
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+/// Maintains live GATT connections to an allowlist of devices alongside the
+/// passive advertisement path, reconnecting with capped exponential backoff.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionManager {
+    allow: Arc<HashSet<String>>,
+    tracked: Arc<Mutex<HashSet<DeviceId>>>,
+    statuses: Arc<Mutex<HashMap<DeviceId, ConnectionState>>>,
+}
+
+impl ConnectionManager {
+    pub fn new(allow: Vec<String>) -> Self {
+        Self {
+            allow: Arc::new(allow.into_iter().collect()),
+            ..Default::default()
+        }
+    }
+
+    /// Start maintaining a connection to `id` if it is allowlisted and not
+    /// already tracked. Safe to call on every event for the device.
+    pub fn consider(&self, session: &BluetoothSession, id: &DeviceId) {
+        if !self.allow.contains(&id.to_string()) {
+            return;
+        }
+        if self.tracked.lock().unwrap().insert(id.clone()) {
+            debug!(dev = %id, "🔗 Tracking device for active connection");
+            let session = session.clone();
+            let id = id.clone();
+            let statuses = self.statuses.clone();
+            tokio::spawn(async move { maintain(session, id, statuses).await });
+        }
+    }
+
+    /// Record a connection state change observed elsewhere (e.g. in the main
+    /// event loop) for a tracked device.
+    pub fn observe(&self, id: &DeviceId, connected: bool) {
+        if self.tracked.lock().unwrap().contains(id) {
+            let state = if connected {
+                ConnectionState::Connected
+            } else {
+                ConnectionState::Disconnected
+            };
+            self.set(id, state);
+        }
+    }
+
+    /// A snapshot of every tracked device's connection state.
+    pub fn statuses(&self) -> HashMap<DeviceId, ConnectionState> {
+        self.statuses.lock().unwrap().clone()
+    }
+
+    fn set(&self, id: &DeviceId, state: ConnectionState) {
+        self.statuses.lock().unwrap().insert(id.clone(), state);
+    }
+}
+
+/// Hold a live connection to one device, reconnecting forever with capped,
+/// jittered exponential backoff whenever it drops.
+async fn maintain(
+    session: BluetoothSession,
+    id: DeviceId,
+    statuses: Arc<Mutex<HashMap<DeviceId, ConnectionState>>>,
+) {
+    let set = |state| {
+        statuses.lock().unwrap().insert(id.clone(), state);
+    };
+    set(ConnectionState::Discovered);
+
+    let mut backoff = BACKOFF_START;
+    loop {
+        set(ConnectionState::Connecting);
+        match session.connect(&id).await {
+            Ok(()) => {
+                debug!(dev = %id, "🔗 Connected");
+                set(ConnectionState::Connected);
+                backoff = BACKOFF_START;
+                wait_for_disconnect(&session, &id).await;
+                debug!(dev = %id, "🔌 Disconnected");
+                set(ConnectionState::Disconnected);
+            }
+            Err(err) => {
+                warn!(dev = %id, ?err, "Connection attempt failed");
+                set(ConnectionState::Disconnected);
+            }
+        }
+
+        let wait = backoff + jitter(backoff);
+        debug!(dev = %id, ?wait, "Reconnecting after backoff");
+        sleep(wait).await;
+        backoff = (backoff * 2).min(BACKOFF_CAP);
+    }
+}
+
+/// Block until the device reports itself disconnected.
+async fn wait_for_disconnect(session: &BluetoothSession, id: &DeviceId) {
+    let mut events = match session.event_stream().await {
+        Ok(events) => events,
+        Err(err) => {
+            warn!(dev = %id, ?err, "Couldn't watch for disconnect");
+            return;
+        }
+    };
+    while let Some(event) = events.next().await {
+        if let BluetoothEvent::Device {
+            id: event_id,
+            event: DeviceEvent::Connected { connected: false },
+        } = event
+        {
+            if &event_id == id {
+                return;
+            }
+        }
+    }
+}
+
+/// Up to one backoff period of jitter, to avoid every device reconnecting in
+/// lockstep after a shared outage.
+fn jitter(base: Duration) -> Duration {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    base.mul_f64(nanos as f64 / 1_000_000_000.0)
+}
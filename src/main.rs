@@ -1,17 +1,21 @@
 mod aranet4;
+mod connection;
 mod mitherm;
+mod output;
 
 use anyhow::{anyhow, Error, Result};
 use bluez_async::{
     AdapterEvent, BluetoothEvent, BluetoothSession, DeviceEvent, DeviceId, DeviceInfo, uuid_from_u16
 };
 use chrono::{DateTime, Duration, Utc};
-use clap::{Parser, ValueEnum};
-use itertools::Itertools;
+use clap::{Parser, Subcommand, ValueEnum};
+use output::OutputSink;
 use scroll::Pread;
-use serde::Serialize;
-use std::{collections::HashMap, io::Write};
-use tokio::select;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+use tokio::{select, sync::mpsc};
 use tokio_stream::StreamExt;
 use tracing::{debug, error, instrument, warn};
 
@@ -40,9 +44,58 @@ struct Args {
     #[arg(long, short, default_value = "60")]
     stale: f64,
 
-    /// Format to output in.
+    /// Format to output in. Ignored when `--config` is given.
     #[arg(long, short = 'F', default_value = "json")]
     output_format: OutputFormat,
+
+    /// YAML file describing the output sinks to fan out to. When absent a
+    /// single sink is built from `--output-format`, `--interval` and `--stale`.
+    #[arg(long, short = 'c')]
+    config: Option<PathBuf>,
+
+    /// Actively connect to discovered Aranet4s to backfill their logged
+    /// history. This interrupts the device's own logging cadence.
+    #[arg(long)]
+    connect: bool,
+
+    /// Device id to hold a persistent connection to, reconnecting with
+    /// backoff. May be given more than once.
+    #[arg(long = "track")]
+    track: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write configuration to an Aranet4 over GATT, instead of monitoring.
+    Config(ConfigArgs),
+}
+
+#[derive(Parser, Debug)]
+struct ConfigArgs {
+    /// Device id or name to configure.
+    #[arg(long)]
+    device: String,
+
+    /// Set the measurement interval, in seconds.
+    #[arg(long)]
+    interval: Option<u16>,
+
+    /// Toggle the smart-home integration broadcast mode.
+    #[arg(long = "smart-home")]
+    smart_home: Option<OnOff>,
+
+    /// Trigger a manual CO2 calibration.
+    #[arg(long)]
+    calibrate: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum)]
+enum OnOff {
+    On,
+    Off,
 }
 
 #[instrument(skip_all)]
@@ -69,6 +122,11 @@ async fn main() -> Result<()> {
     // Connect to BlueZ over DBus.
     let (bt_join_handle, session) = BluetoothSession::new().await?;
 
+    // The config subcommand is a one-shot write, distinct from the monitor loop.
+    if let Some(Command::Config(config)) = &args.command {
+        return run_config(&session, config).await;
+    }
+
     // Spawn a background task that processes Bluetooth events.
     tokio::spawn(async move { run(&args, session).await });
 
@@ -77,36 +135,160 @@ async fn main() -> Result<()> {
     Err(anyhow!("Bluetooth Session terminated!"))
 }
 
+/// Resolve the target device, apply the requested configuration commands and
+/// report the device's acknowledgements.
+async fn run_config(session: &BluetoothSession, config: &ConfigArgs) -> Result<()> {
+    // Give discovery a moment to turn up the device before we look for it.
+    session.start_discovery().await?;
+    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    let devices = session.get_devices().await?;
+    session.stop_discovery().await?;
+
+    let device = devices
+        .into_iter()
+        .find(|info| {
+            info.id.to_string() == config.device || info.name.as_deref() == Some(&config.device)
+        })
+        .map(|info| info.id)
+        .ok_or_else(|| anyhow!("no device matching {:?}", config.device))?;
+
+    let mut commands = Vec::new();
+    if let Some(interval) = config.interval {
+        commands.push(aranet4::Command::SetInterval(interval));
+    }
+    if let Some(smart_home) = config.smart_home {
+        commands.push(aranet4::Command::SmartHome(smart_home == OnOff::On));
+    }
+    if config.calibrate {
+        commands.push(aranet4::Command::Calibrate);
+    }
+    if commands.is_empty() {
+        return Err(anyhow!("no configuration changes requested"));
+    }
+
+    for response in aranet4::configure(session, &device, &commands).await? {
+        debug!(?response, "⚙️ Command acknowledged");
+    }
+    Ok(())
+}
+
 #[derive(Debug, Default)]
 struct State {
     aranet4: HashMap<DeviceId, (DateTime<Utc>, aranet4::Announcement)>,
+    aranet4_history: HashMap<DeviceId, aranet4::History>,
+    /// Devices whose history download is in flight, so we only spawn one.
+    aranet4_pending: HashSet<DeviceId>,
+    /// Backfilled histories come back here from the download tasks.
+    history_tx: Option<mpsc::Sender<(DeviceId, aranet4::History)>>,
+    mitherm: HashMap<DeviceId, (DateTime<Utc>, mitherm::Announcement)>,
     devices: HashMap<DeviceId, DeviceInfo>,
+    connections: connection::ConnectionManager,
+}
+
+impl State {
+    /// Collect the readings keyed by device name, ready to be handed to a sink
+    /// for formatting. Announcements older than `stale` are dropped; backfilled
+    /// histories and connection states are always included.
+    fn rendered(&self, stale: Duration) -> output::Rendered<'_> {
+        let now = Utc::now();
+        let device_name = |id: &DeviceId| {
+            self.devices
+                .get(id)
+                .and_then(|info| info.name.clone())
+                .unwrap_or_else(|| id.to_string())
+        };
+        output::Rendered {
+            aranet4: self
+                .aranet4
+                .iter()
+                .filter(|(_, (ts, _))| now - ts < stale)
+                .map(|(id, (_, ann))| (device_name(id), ann))
+                .collect(),
+            aranet4_history: self
+                .aranet4_history
+                .iter()
+                .map(|(id, history)| (device_name(id), history))
+                .collect(),
+            mitherm: self
+                .mitherm
+                .iter()
+                .filter(|(_, (ts, _))| now - ts < stale)
+                .map(|(id, (_, ann))| (device_name(id), ann))
+                .collect(),
+            connections: self
+                .connections
+                .statuses()
+                .into_iter()
+                .map(|(id, state)| (device_name(&id), state))
+                .collect(),
+        }
+    }
 }
 
 #[instrument(skip_all)]
 async fn run(args: &Args, session: BluetoothSession) {
     let mut state = State::default();
-    let mut output_ticker =
-        tokio::time::interval(std::time::Duration::from_secs_f64(args.interval));
-    let mut events = session.event_stream().await.unwrap();
+    state.connections = connection::ConnectionManager::new(args.track.clone());
+
+    // History downloads run off the select path and report back here.
+    let (history_tx, mut history_rx) = mpsc::channel::<(DeviceId, aranet4::History)>(8);
+    state.history_tx = Some(history_tx);
+
+    let mut sinks = match build_sinks(args) {
+        Ok(sinks) => sinks,
+        Err(err) => {
+            error!(?err, "Couldn't build output sinks");
+            return;
+        }
+    };
+
+    // Each sink emits on its own schedule; a ticker task per sink pushes its
+    // index down a shared channel that the event loop dispatches on.
+    let (tick_tx, mut tick_rx) = mpsc::channel::<usize>(sinks.len().max(1));
+    for (index, (interval, _)) in sinks.iter().enumerate() {
+        let tick_tx = tick_tx.clone();
+        let period = std::time::Duration::from_secs_f64(*interval);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            loop {
+                ticker.tick().await;
+                if tick_tx.send(index).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(tick_tx);
 
+    let mut events = session.event_stream().await.unwrap();
     session.start_discovery().await.unwrap();
     loop {
         enum Poll {
-            OutputTick,
+            OutputTick(usize),
+            History(DeviceId, aranet4::History),
             Event(BluetoothEvent),
         }
         match select! {
-            _ = output_ticker.tick() => Poll::OutputTick,
+            Some(index) = tick_rx.recv() => Poll::OutputTick(index),
+            Some((id, history)) = history_rx.recv() => Poll::History(id, history),
             Some(event) = events.next() => Poll::Event(event),
         } {
-            Poll::OutputTick => {
-                if let Err(err) = print_state(args, &state) {
-                    error!(?err, "Couldn't print state");
+            Poll::OutputTick(index) => {
+                if let Err(err) = sinks[index].1.emit(&state) {
+                    error!(?err, "Couldn't emit to sink");
                 }
             }
+            Poll::History(id, history) => {
+                debug!(
+                    dev = format!("{}", id),
+                    samples = history.samples.len(),
+                    "⬇️ Backfilled Aranet4 history"
+                );
+                state.aranet4_pending.remove(&id);
+                state.aranet4_history.insert(id, history);
+            }
             Poll::Event(event) => {
-                if let Err(err) = process_event(&mut state, &session, &event).await {
+                if let Err(err) = process_event(args, &mut state, &session, &event).await {
                     error!(?event, ?err, "Event Error");
                 };
             }
@@ -114,93 +296,31 @@ async fn run(args: &Args, session: BluetoothSession) {
     }
 }
 
-fn print_state(args: &Args, state: &State) -> Result<()> {
-    #[derive(Debug, Serialize)]
-    struct Output<'s> {
-        #[serde(skip_serializing_if = "HashMap::is_empty")]
-        pub aranet4: HashMap<String, &'s aranet4::Announcement>,
-    }
-
-    // Accumulate output data.
-    let now = Utc::now();
-    let stale = Duration::from_std(std::time::Duration::from_secs_f64(args.stale)).unwrap();
-    let output = Output {
-        aranet4: state
-            .aranet4
-            .iter()
-            .filter(|(_, (ts, _))| now - ts < stale)
-            .map(|(id, (_, ann))| {
-                (
-                    state
-                        .devices
-                        .get(id)
-                        .and_then(|info| info.name.clone())
-                        .unwrap_or_else(|| id.to_string()),
-                    ann,
-                )
-            })
-            .collect(),
-    };
-    // Don't log anything if there's no non-stale data.
-    if output.aranet4.is_empty() {
-        return Ok(());
-    }
-    debug!("{:?}", &output);
-
-    // Write to stdout.
-    let mut stdout = std::io::stdout();
-    match args.output_format {
-        OutputFormat::Json => {
-            serde_json::to_writer_pretty(&mut stdout, &output)?;
-            writeln!(&mut stdout)?;
-        }
-        OutputFormat::Waybar => {
-            // Format and sort the readings by CO2 value.
-            let mut aranet4: Vec<(&String, u16, String)> = output
-                .aranet4
-                .iter()
-                .map(|(id, ann)| {
-                    let s = format!(
-                        "🪟 {} 🌡️ {:.2} ☔ {} 🗜️ {:.0}",
-                        ann.co2.map(i32::from).unwrap_or(-1),
-                        ann.temperature.unwrap_or(-1.0),
-                        ann.humidity,
-                        ann.pressure.unwrap_or(-1.0),
-                    );
-                    (id, ann.co2.unwrap_or_default(), s)
-                })
-                .collect();
-            aranet4.sort_by_key(|(_, co2, _)| -(*co2 as i32)); // hack to sort descending
-
-            // Each line is one reading.
-            #[derive(Serialize)]
-            struct WaybarOutput<'a> {
-                pub text: &'a str,
-                pub tooltip: String,
-            }
-            serde_json::to_writer(
-                &mut stdout,
-                &WaybarOutput {
-                    text: aranet4
-                        .first()
-                        .map(|(_, _, s)| s.as_str())
-                        .unwrap_or_default(),
-                    tooltip: aranet4
-                        .iter()
-                        .map(|(id, _, s)| format!("[{}] {}", id, s))
-                        .join("\n"),
+/// Build the configured sinks, falling back to a single sink described by the
+/// legacy command-line flags when no config file is given.
+fn build_sinks(args: &Args) -> Result<Vec<(f64, Box<dyn OutputSink + Send>)>> {
+    let config = match &args.config {
+        Some(path) => output::Config::load(path)?,
+        None => output::Config {
+            sinks: vec![output::SinkConfig {
+                kind: match args.output_format {
+                    OutputFormat::Json => output::SinkKind::Json,
+                    OutputFormat::Waybar => output::SinkKind::Waybar,
                 },
-            )?;
-            writeln!(&mut stdout)?;
-        }
-    }
-    stdout.flush()?;
-
-    Ok(())
+                destination: output::Destination::Stdout,
+                interval: args.interval,
+                stale: args.stale,
+                mqtt: None,
+            }],
+        },
+    };
+    config.sinks.iter().map(output::SinkConfig::build).collect()
 }
 
+
 #[instrument(skip_all)]
 async fn process_event(
+    args: &Args,
     state: &mut State,
     session: &BluetoothSession,
     event: &BluetoothEvent,
@@ -218,26 +338,112 @@ async fn process_event(
             }
             _ => {}
         },
-        BluetoothEvent::Device { id, event } => match event {
-            DeviceEvent::ManufacturerData { manufacturer_data } => {
-                for (key, value) in manufacturer_data {
-                    match *key {
-                        aranet4::MANUFACTURER_ID => {
-                            let ann = value.pread::<aranet4::Announcement>(0)?;
+        BluetoothEvent::Device { id, event } => {
+            // Start (or keep) a persistent connection for allowlisted devices.
+            state.connections.consider(session, id);
+            match event {
+                DeviceEvent::Connected { connected } => {
+                    debug!(dev = format!("{}", id), connected, "🔗 Connection State");
+                    state.connections.observe(id, *connected);
+                }
+                DeviceEvent::ManufacturerData { manufacturer_data } => {
+                    for (key, value) in manufacturer_data {
+                        match *key {
+                            aranet4::MANUFACTURER_ID => {
+                                let ann = value.pread::<aranet4::Announcement>(0)?;
+                                debug!(
+                                    dev = format!("{}", id),
+                                    co2 = ann.co2,
+                                    temp = ann.temperature,
+                                    press = ann.pressure,
+                                    humid = ann.humidity,
+                                    bat = ann.battery,
+                                    status = ann.status,
+                                    "🌬️ Aranet4 announcement"
+                                );
+                                state.aranet4.insert(id.clone(), (Utc::now(), ann));
+
+                                if !state.devices.contains_key(id) {
+                                    debug!(dev = format!("{}", id), "Getting device info...");
+                                    match session.get_device_info(id).await {
+                                        Ok(info) => {
+                                            state.devices.insert(id.clone(), info);
+                                        }
+                                        Err(err) => warn!(
+                                            dev = format!("{}", id),
+                                            ?err,
+                                            "Couldn't get device info"
+                                        ),
+                                    }
+                                }
+
+                                // Backfill the logged history once per device,
+                                // off the event loop so the multi-second connect
+                                // and per-parameter reads don't stall it.
+                                if args.connect
+                                    && !state.aranet4_history.contains_key(id)
+                                    && state.aranet4_pending.insert(id.clone())
+                                {
+                                    if let Some(tx) = state.history_tx.clone() {
+                                        let session = session.clone();
+                                        let id = id.clone();
+                                        tokio::spawn(async move {
+                                            match aranet4::fetch_history(&session, &id).await {
+                                                Ok(history) => {
+                                                    let _ = tx.send((id, history)).await;
+                                                }
+                                                Err(err) => warn!(
+                                                    dev = format!("{}", id),
+                                                    ?err,
+                                                    "Couldn't download history"
+                                                ),
+                                            }
+                                        });
+                                    }
+                                }
+                            }
+                            0x004C => debug!(
+                                dev = format!("{}", id),
+                                value = hex::encode(value),
+                                "🍏 Apple Data"
+                            ),
+                            _ => debug!(
+                                dev = format!("{}", id),
+                                key = format!("{:04X}", key),
+                                value = hex::encode(value),
+                                "🔢 Manufacturer Data"
+                            ),
+                        }
+                    }
+                }
+                DeviceEvent::ServiceData { service_data } => {
+                    for (svc, value) in service_data {
+                        debug!(
+                            dev = format!("{}", id),
+                            svc = format!("{}", svc),
+                            value = hex::encode(value),
+                            "⚙️ Service Data"
+                        );
+                        let layout = if *svc == uuid_from_u16(mitherm::BTHOME_UUID) {
+                            Some(mitherm::Layout::BtHome)
+                        } else if *svc == uuid_from_u16(mitherm::ATC1441_UUID) {
+                            Some(mitherm::Layout::Atc1441)
+                        } else {
+                            None
+                        };
+                        if let Some(layout) = layout {
+                            let ann = value.pread_with::<mitherm::Announcement>(0, layout)?;
                             debug!(
                                 dev = format!("{}", id),
-                                co2 = ann.co2,
                                 temp = ann.temperature,
-                                press = ann.pressure,
                                 humid = ann.humidity,
                                 bat = ann.battery,
-                                status = ann.status,
-                                "🌬️ Aranet4 announcement"
+                                volt = ann.voltage,
+                                "🌬️ Mitherm announcement"
                             );
-                            state.aranet4.insert(id.clone(), (Utc::now(), ann));
+                            state.mitherm.insert(id.clone(), (Utc::now(), ann));
 
                             if !state.devices.contains_key(id) {
-                                debug!(dev = format!("{}", id), "Getting device info...");
                                 match session.get_device_info(id).await {
                                     Ok(info) => {
                                         state.devices.insert(id.clone(), info);
@@ -250,44 +456,11 @@ async fn process_event(
                                 }
                             }
                         }
-                        0x004C => debug!(
-                            dev = format!("{}", id),
-                            value = hex::encode(value),
-                            "🍏 Apple Data"
-                        ),
-                        _ => debug!(
-                            dev = format!("{}", id),
-                            key = format!("{:04X}", key),
-                            value = hex::encode(value),
-                            "🔢 Manufacturer Data"
-                        ),
                     }
                 }
+                _ => {}
             }
-            DeviceEvent::ServiceData { service_data } => {
-                for (svc, value) in service_data {
-					let uuid = uuid_from_u16(0x181A);
-					debug!( svc = format!("{}", svc), uuid = format!("{}", uuid));
-					debug!(
-						dev = format!("{}", id),
-						svc = format!("{}", svc),
-						value = hex::encode(value),
-						"⚙️ Service Data"
-					);					
-					if *svc == uuid {
-						let ann = value.pread::<mitherm::Announcement>(0)?;
-                        debug!(
-                                dev = format!("{}", id),
-                                temp = ann.temperature,
-                                humid = ann.humidity,
-                                bat = ann.battery_mv,
-                                "🌬️ Mitherm announcement"
-                        );
-					}
-                }
-            }
-            _ => {}
-        },
+        }
         _ => {}
     }
     Ok(())
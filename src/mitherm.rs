@@ -1,57 +1,162 @@
 use crate::{Error, Result};
+use anyhow::anyhow;
 use scroll::{ctx::TryFromCtx, Endian, Pread};
 use serde::Serialize;
+use tracing::debug;
+
+/// Which service-data layout a payload is in, selected from the service UUID
+/// it was advertised under and threaded through as the decode context.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    BtHome,
+    Atc1441,
+}
 
 // pub const MANUFACTURER_ID: u16 = 0xa4c1;
 
-#[derive(Debug, Clone, PartialEq, Serialize)]
+/// BTHome v2 service data lives under this 16-bit service UUID.
+pub const BTHOME_UUID: u16 = 0xFCD2;
+/// The legacy ATC1441 firmware broadcasts under the environmental sensing
+/// service instead.
+pub const ATC1441_UUID: u16 = 0x181A;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
 pub struct Announcement {
-    pub temperature: f64,
-    pub humidity: f64,
-    pub battery_mv: u16,
-	pub battery_percent: u8,
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub battery: Option<u8>,
+    pub voltage: Option<f64>,
 }
 
-impl<'a> TryFromCtx<'a, ()> for Announcement { // this is the pvvx custom firmware format, apparently
+impl<'a> TryFromCtx<'a, Layout> for Announcement {
     type Error = Error;
-    fn try_from_ctx(from: &'a [u8], _: ()) -> Result<(Self, usize)> {
+    fn try_from_ctx(from: &'a [u8], layout: Layout) -> Result<(Self, usize)> {
+        // The two layouts share no framing and a BTHome payload can be exactly
+        // as long as an ATC1441 one, so dispatch on the service UUID the caller
+        // matched rather than guessing from the bytes.
+        match layout {
+            Layout::BtHome => Self::from_bthome(from),
+            Layout::Atc1441 => Self::from_atc1441(from),
+        }
+    }
+}
+
+impl Announcement {
+    /// Decode a BTHome v2 payload: a device-info flag byte followed by a
+    /// concatenation of objects, each a one-byte id and a fixed-width
+    /// little-endian value.
+    fn from_bthome(from: &[u8]) -> Result<(Self, usize)> {
+        // The device-info byte's top three bits hold the version, which must
+        // be 2 for the layout we decode below.
+        if from.is_empty() || from[0] >> 5 != 2 {
+            return Err(anyhow!("not a BTHome v2 payload"));
+        }
+        if from[0] & 0x01 != 0 {
+            return Err(anyhow!("encrypted BTHome payloads are unsupported"));
+        }
+
+        let mut ann = Announcement::default();
+        let mut offset = 1;
+        while offset < from.len() {
+            let id: u8 = from.gread(&mut offset)?;
+            match id {
+                0x01 => ann.battery = Some(from.gread(&mut offset)?),
+                0x02 => {
+                    ann.temperature =
+                        Some(from.gread_with::<i16>(&mut offset, Endian::Little)? as f64 * 0.01)
+                }
+                0x03 => {
+                    ann.humidity =
+                        Some(from.gread_with::<u16>(&mut offset, Endian::Little)? as f64 * 0.01)
+                }
+                0x0C => {
+                    ann.voltage =
+                        Some(from.gread_with::<u16>(&mut offset, Endian::Little)? as f64 * 0.001)
+                }
+                // Packet id / counter; consumed but not surfaced.
+                0x00 | 0x09 => offset += 1,
+                // Ids we don't decode are skipped by their known length. Objects
+                // have no self-describing length, so an id whose length we don't
+                // know ends decoding here, keeping whatever was already decoded.
+                other => match object_len(other) {
+                    Some(len) => offset += len,
+                    None => {
+                        debug!(id = format!("{other:#04x}"), "stopping at unknown BTHome object");
+                        break;
+                    }
+                },
+            }
+        }
+        Ok((ann, offset))
+    }
+
+    /// Decode the legacy ATC1441 layout: a 6-byte MAC followed by a big-endian
+    /// signed temperature (×0.1 °C), humidity %, battery %, and battery mV.
+    fn from_atc1441(from: &[u8]) -> Result<(Self, usize)> {
         let mut offset = 6;
         Ok((
             Self {
-                temperature: from
-                    .gread_with::<u16>(&mut offset, Endian::Little)
-                    .map(|v| v as f64 * 0.01)?,
-                humidity: from
-                    .gread_with::<u16>(&mut offset, Endian::Little)
-                    .map(|v| v as f64 * 0.01)?,
-                battery_mv: from
-                    .gread_with::<u16>(&mut offset, Endian::Little)?,
-                battery_percent: from.gread(&mut offset)?
+                temperature: Some(
+                    from.gread_with::<i16>(&mut offset, Endian::Big)? as f64 * 0.1,
+                ),
+                humidity: Some(from.gread::<u8>(&mut offset)? as f64),
+                battery: Some(from.gread(&mut offset)?),
+                voltage: Some(from.gread_with::<u16>(&mut offset, Endian::Big)? as f64 * 0.001),
             },
             offset,
         ))
     }
 }
 
+/// Byte length of a BTHome v2 object we don't decode, so it can be skipped.
+fn object_len(id: u8) -> Option<usize> {
+    Some(match id {
+        0x04 => 3, // pressure
+        0x05 => 3, // illuminance
+        0x06 => 2, // mass (kg)
+        0x08 => 2, // dewpoint
+        0x0A => 3, // energy
+        0x0B => 3, // power
+        0x3A => 1, // button event
+        _ => return None,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_announcement() {
+    fn test_bthome_v2() {
         assert_eq!(
             Announcement {
-                temperature: 22.38,
-                humidity: 54.44,
-                battery_percent: 100,
-                battery_mv: 3004,
+                temperature: Some(25.0),
+                humidity: Some(50.550000000000004),
+                battery: Some(97),
+                voltage: Some(3.0),
             },
             [
-				0x80,0x49,0xd8,0x38,0xc1,0xa4,0xbe,0x08,0x44,0x15,0xbc,0x0b,0x64,0xef,0x04
+                0x40, 0x01, 0x61, 0x02, 0xc4, 0x09, 0x03, 0xbf, 0x13, 0x0c, 0xb8, 0x0b,
             ]
-            .pread(0)
+            .pread_with(0, Layout::BtHome)
             .unwrap()
         );
     }
-}
 
+    #[test]
+    fn test_atc1441() {
+        assert_eq!(
+            Announcement {
+                temperature: Some(23.0),
+                humidity: Some(55.0),
+                battery: Some(88),
+                voltage: Some(3.0),
+            },
+            [
+                0xa4, 0xc1, 0x38, 0xd8, 0x49, 0x80, 0x00, 0xe6, 0x37, 0x58, 0x0b, 0xb8,
+            ]
+            .pread_with(0, Layout::Atc1441)
+            .unwrap()
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use crate::{aranet4, connection, mitherm, Result, State};
+use anyhow::anyhow;
+use chrono::Duration;
+use itertools::Itertools;
+use rumqttc::{Client, MqttOptions, QoS};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+};
+use tracing::debug;
+
+/// A snapshot of the readings, keyed by device name, ready to be formatted by
+/// a sink. Only the announcement maps are filtered for staleness.
+#[derive(Debug, Serialize)]
+pub struct Rendered<'s> {
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub aranet4: HashMap<String, &'s aranet4::Announcement>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub aranet4_history: HashMap<String, &'s aranet4::History>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub mitherm: HashMap<String, &'s mitherm::Announcement>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub connections: HashMap<String, connection::ConnectionState>,
+}
+
+impl Rendered<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.aranet4.is_empty()
+            && self.aranet4_history.is_empty()
+            && self.mitherm.is_empty()
+            && self.connections.is_empty()
+    }
+}
+
+/// Something that can turn the current [`State`] into a line of output on its
+/// own schedule. Each implementor applies its own staleness threshold.
+pub trait OutputSink {
+    fn emit(&mut self, state: &State) -> Result<()>;
+}
+
+/// Open the writer a sink should flush to.
+fn open(destination: &Destination) -> Result<Box<dyn Write + Send>> {
+    Ok(match destination {
+        Destination::Stdout => Box::new(std::io::stdout()),
+        Destination::File(path) => {
+            Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        }
+    })
+}
+
+/// Pretty-printed JSON of the whole state.
+pub struct JsonSink {
+    writer: Box<dyn Write + Send>,
+    stale: Duration,
+}
+
+impl OutputSink for JsonSink {
+    fn emit(&mut self, state: &State) -> Result<()> {
+        let rendered = state.rendered(self.stale);
+        if rendered.is_empty() {
+            return Ok(());
+        }
+        serde_json::to_writer_pretty(&mut self.writer, &rendered)?;
+        writeln!(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// A single-line Waybar module, with the busiest Aranet4 on the bar and every
+/// reading in the tooltip.
+pub struct WaybarSink {
+    writer: Box<dyn Write + Send>,
+    stale: Duration,
+}
+
+impl OutputSink for WaybarSink {
+    fn emit(&mut self, state: &State) -> Result<()> {
+        let rendered = state.rendered(self.stale);
+        if rendered.is_empty() {
+            return Ok(());
+        }
+
+        // Format and sort the readings by CO2 value.
+        let mut aranet4: Vec<(&String, u16, String)> = rendered
+            .aranet4
+            .iter()
+            .map(|(id, ann)| {
+                let s = format!(
+                    "🪟 {} 🌡️ {:.2} ☔ {} 🗜️ {:.0}",
+                    ann.co2.map(i32::from).unwrap_or(-1),
+                    ann.temperature.unwrap_or(-1.0),
+                    ann.humidity,
+                    ann.pressure.unwrap_or(-1.0),
+                );
+                (id, ann.co2.unwrap_or_default(), s)
+            })
+            .collect();
+        aranet4.sort_by_key(|(_, co2, _)| -(*co2 as i32)); // hack to sort descending
+
+        // Mitherm sensors only report temperature/humidity, so they get their
+        // own tooltip lines below the Aranet4 bar.
+        let mitherm = rendered.mitherm.iter().map(|(id, ann)| {
+            format!(
+                "[{}] 🌡️ {:.2} ☔ {:.0}",
+                id,
+                ann.temperature.unwrap_or(-1.0),
+                ann.humidity.unwrap_or(-1.0),
+            )
+        });
+
+        // Connection status of any actively-tracked devices.
+        let connections = rendered
+            .connections
+            .iter()
+            .map(|(id, state)| format!("🔗 [{}] {:?}", id, state));
+
+        #[derive(Serialize)]
+        struct WaybarOutput<'a> {
+            pub text: &'a str,
+            pub tooltip: String,
+        }
+        serde_json::to_writer(
+            &mut self.writer,
+            &WaybarOutput {
+                text: aranet4
+                    .first()
+                    .map(|(_, _, s)| s.as_str())
+                    .unwrap_or_default(),
+                tooltip: aranet4
+                    .iter()
+                    .map(|(id, _, s)| format!("[{}] {}", id, s))
+                    .chain(mitherm)
+                    .chain(connections)
+                    .join("\n"),
+            },
+        )?;
+        writeln!(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Publishes each Aranet4's latest reading to an MQTT broker, optionally
+/// emitting Home Assistant discovery configs so the sensors auto-register.
+/// Mitherm readings are not published.
+pub struct MqttSink {
+    client: Client,
+    prefix: String,
+    qos: QoS,
+    discovery: bool,
+    /// Entities we've already published discovery configs for.
+    discovered: HashSet<String>,
+    stale: Duration,
+}
+
+/// A single Home Assistant measurement exposed per device.
+struct Measurement {
+    key: &'static str,
+    unit: &'static str,
+    device_class: &'static str,
+}
+
+const MEASUREMENTS: [Measurement; 5] = [
+    Measurement { key: "co2", unit: "ppm", device_class: "carbon_dioxide" },
+    Measurement { key: "temperature", unit: "°C", device_class: "temperature" },
+    Measurement { key: "pressure", unit: "hPa", device_class: "pressure" },
+    Measurement { key: "humidity", unit: "%", device_class: "humidity" },
+    Measurement { key: "battery", unit: "%", device_class: "battery" },
+];
+
+/// Turn a device name into an MQTT/Home Assistant safe object id.
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+fn qos(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+impl MqttSink {
+    fn new(config: &MqttConfig, stale: Duration) -> Result<Self> {
+        let mut options = MqttOptions::new("arara", &config.host, config.port);
+        options.set_keep_alive(std::time::Duration::from_secs(30));
+        if let (Some(user), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(user, password);
+        }
+        let (client, mut connection) = Client::new(options, 10);
+        // rumqttc reconnects as long as the event loop keeps being polled, so
+        // drain it on a background thread for its whole lifetime.
+        std::thread::spawn(move || {
+            for event in connection.iter() {
+                if let Err(err) = event {
+                    debug!(?err, "MQTT connection event");
+                }
+            }
+        });
+        Ok(Self {
+            client,
+            prefix: config.prefix.clone(),
+            qos: qos(config.qos),
+            discovery: config.discovery,
+            discovered: HashSet::new(),
+            stale,
+        })
+    }
+}
+
+impl OutputSink for MqttSink {
+    fn emit(&mut self, state: &State) -> Result<()> {
+        #[derive(Serialize)]
+        struct Discovery<'a> {
+            name: String,
+            state_topic: &'a str,
+            value_template: String,
+            unique_id: String,
+            unit_of_measurement: &'a str,
+            device_class: &'a str,
+        }
+
+        for (name, ann) in &state.rendered(self.stale).aranet4 {
+            let entity = slug(name);
+            let state_topic = format!("{}/{}", self.prefix, entity);
+
+            // One retained discovery config per measurement, published once.
+            if self.discovery && self.discovered.insert(entity.clone()) {
+                for m in &MEASUREMENTS {
+                    let payload = serde_json::to_vec(&Discovery {
+                        name: format!("{name} {}", m.key),
+                        state_topic: &state_topic,
+                        value_template: format!("{{{{ value_json.{} }}}}", m.key),
+                        unique_id: format!("arara_{entity}_{}", m.key),
+                        unit_of_measurement: m.unit,
+                        device_class: m.device_class,
+                    })?;
+                    self.client.publish(
+                        format!("homeassistant/sensor/{entity}/{}/config", m.key),
+                        self.qos,
+                        true,
+                        payload,
+                    )?;
+                }
+            }
+
+            self.client
+                .publish(&state_topic, self.qos, false, serde_json::to_vec(ann)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a sink writes to.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Destination {
+    #[default]
+    Stdout,
+    File(PathBuf),
+}
+
+/// The output kinds that can be listed in the config file.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    Json,
+    Waybar,
+    Mqtt,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_prefix() -> String {
+    "arara".to_string()
+}
+
+/// Connection details for an [`MqttSink`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topic prefix device state is published under.
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// MQTT QoS level (0, 1 or 2).
+    #[serde(default)]
+    pub qos: u8,
+    /// Emit Home Assistant discovery configs for each device.
+    #[serde(default)]
+    pub discovery: bool,
+}
+
+fn default_interval() -> f64 {
+    2.0
+}
+
+fn default_stale() -> f64 {
+    60.0
+}
+
+/// A single configured sink.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SinkConfig {
+    #[serde(rename = "type")]
+    pub kind: SinkKind,
+    #[serde(default)]
+    pub destination: Destination,
+    /// How often (seconds) this sink emits.
+    #[serde(default = "default_interval")]
+    pub interval: f64,
+    /// How old (seconds) a reading may be before this sink drops it.
+    #[serde(default = "default_stale")]
+    pub stale: f64,
+    /// Broker details, required when `type` is `mqtt`.
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+}
+
+/// The whole fan-out configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub sinks: Vec<SinkConfig>,
+}
+
+impl Config {
+    /// Parse a YAML config file from disk.
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        Ok(serde_yaml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+impl SinkConfig {
+    /// Build the live sink and return it alongside its emit interval.
+    pub fn build(&self) -> Result<(f64, Box<dyn OutputSink + Send>)> {
+        let stale = Duration::from_std(std::time::Duration::from_secs_f64(self.stale))?;
+        let sink: Box<dyn OutputSink + Send> = match self.kind {
+            SinkKind::Json => Box::new(JsonSink { writer: open(&self.destination)?, stale }),
+            SinkKind::Waybar => Box::new(WaybarSink { writer: open(&self.destination)?, stale }),
+            SinkKind::Mqtt => {
+                let config = self
+                    .mqtt
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("mqtt sink requires an `mqtt` config block"))?;
+                Box::new(MqttSink::new(config, stale)?)
+            }
+        };
+        Ok((self.interval, sink))
+    }
+}